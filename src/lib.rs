@@ -50,6 +50,7 @@ extern crate multiboot;
 extern crate x86;
 #[macro_use]
 extern crate log;
+extern crate linked_list_allocator;
 
 #[macro_use]
 mod macros;
@@ -92,9 +93,16 @@ pub extern "C" fn sys_malloc(size: usize, align: usize) -> *mut u8 {
 	let ptr;
 
 	unsafe {
+		// `ALLOCATOR.alloc` already tries to grow the heap once on its own
+		// if the request doesn't fit, so there is no extra retry to do
+		// here. A null result at this point means growth failed too.
 		ptr = ALLOCATOR.alloc(layout);
 	}
 
+	if ptr.is_null() {
+		runtime_glue::rust_oom(layout);
+	}
+
 	trace!(
 		"sys_malloc: allocate memory at 0x{:x} (size 0x{:x}, align 0x{:x})",
 		ptr as usize,
@@ -227,6 +235,12 @@ fn boot_processor_main() -> ! {
 	);
 
 	arch::boot_processor_init();
+
+	// Let the heap grow into fresh physical memory instead of hard-aborting
+	// the first time an allocation doesn't fit.
+	#[cfg(not(test))]
+	ALLOCATOR.set_growth_callback(mm::grow_heap);
+
 	scheduler::init();
 	scheduler::add_current_core();
 
@@ -0,0 +1,117 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The kernel's global heap allocator.
+//!
+//! Wraps a `linked_list_allocator::Heap` in a `Spinlock` and, when an
+//! allocation doesn't fit, gives the heap one chance to grow before giving
+//! up. Growing the heap itself (reserving virtual address space and mapping
+//! physical pages into it) is architecture/memory-management territory, so
+//! `LockedHeap` only knows about it through a `GrowthFn` callback that
+//! `boot_processor_main` installs -- this file has no idea how a page gets
+//! mapped.
+
+use core::alloc::{GlobalAlloc, Layout};
+use linked_list_allocator::Heap;
+use synch::spinlock::Spinlock;
+
+/// Given the heap's current top address and the number of additional bytes
+/// that are needed at minimum, attempts to grow the heap and returns how
+/// many bytes were actually added, or `None` if no more memory could be
+/// reserved/mapped.
+pub type GrowthFn = fn(current_top: usize, min_additional: usize) -> Option<usize>;
+
+pub struct LockedHeap {
+	heap: Spinlock<Option<Heap>>,
+	growth_fn: Spinlock<Option<GrowthFn>>,
+}
+
+impl LockedHeap {
+	pub const fn empty() -> Self {
+		Self {
+			heap: Spinlock::new(None),
+			growth_fn: Spinlock::new(None),
+		}
+	}
+
+	/// Hands the heap its initial region. Must be called exactly once,
+	/// before the first allocation.
+	pub unsafe fn init(&self, start: usize, size: usize) {
+		*self.heap.lock() = Some(Heap::new(start, size));
+	}
+
+	/// Installs the callback used to grow the heap on demand. Without one,
+	/// a full heap is simply out of memory.
+	pub fn set_growth_callback(&self, growth_fn: GrowthFn) {
+		*self.growth_fn.lock() = Some(growth_fn);
+	}
+
+	/// The current `[bottom, top)` extents of the managed heap region, for
+	/// diagnostics. `(0, 0)` if the heap hasn't been initialized yet.
+	pub fn extents(&self) -> (usize, usize) {
+		match *self.heap.lock() {
+			Some(ref heap) => (heap.bottom(), heap.top()),
+			None => (0, 0),
+		}
+	}
+
+	/// Attempts to grow the heap by at least `min_additional` bytes via the
+	/// installed growth callback. Returns `false` if no callback is
+	/// installed or the callback itself couldn't find more memory.
+	fn try_grow(&self, min_additional: usize) -> bool {
+		let growth_fn = match *self.growth_fn.lock() {
+			Some(f) => f,
+			None => return false,
+		};
+
+		let mut locked_heap = self.heap.lock();
+		let heap = match locked_heap.as_mut() {
+			Some(heap) => heap,
+			None => return false,
+		};
+
+		match growth_fn(heap.top(), min_additional) {
+			Some(grown_by) => {
+				unsafe {
+					heap.extend(grown_by);
+				}
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		{
+			let mut locked_heap = self.heap.lock();
+			let heap = locked_heap.as_mut().expect("heap is not initialized");
+			if let Ok(ptr) = heap.allocate_first_fit(layout) {
+				return ptr.as_ptr();
+			}
+		}
+
+		// The heap is full. Give it one chance to grow before telling the
+		// caller we're out of memory.
+		if !self.try_grow(layout.size()) {
+			return core::ptr::null_mut();
+		}
+
+		let mut locked_heap = self.heap.lock();
+		let heap = locked_heap.as_mut().expect("heap is not initialized");
+		heap.allocate_first_fit(layout)
+			.map(|ptr| ptr.as_ptr())
+			.unwrap_or(core::ptr::null_mut())
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		let mut locked_heap = self.heap.lock();
+		let heap = locked_heap.as_mut().expect("heap is not initialized");
+		heap.deallocate(core::ptr::NonNull::new_unchecked(ptr), layout);
+	}
+}
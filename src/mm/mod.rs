@@ -0,0 +1,20 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+pub mod allocator;
+
+/// Grows the heap by mapping additional physical pages directly after its
+/// current end and returns how many bytes were actually added. Delegates
+/// the actual reservation/mapping to `arch`, which is the only layer that
+/// knows how to walk page tables on this platform; `mm::allocator` just
+/// sees a `GrowthFn` it can call without caring how it works.
+pub fn grow_heap(current_top: usize, min_additional: usize) -> Option<usize> {
+	const GROWTH_STEP: usize = 2 * 1024 * 1024;
+	let size = core::cmp::max(min_additional, GROWTH_STEP);
+
+	arch::mm::extend_heap(current_top, size)
+}
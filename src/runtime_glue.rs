@@ -12,6 +12,8 @@
 use alloc::alloc::Layout;
 use arch;
 use core::panic::PanicInfo;
+#[cfg(not(test))]
+use ALLOCATOR;
 
 // see https://users.rust-lang.org/t/psa-breaking-change-panic-fmt-language-item-removed-in-favor-of-panic-implementation/17875
 #[cfg(not(test))]
@@ -38,10 +40,21 @@ fn panic(info: &PanicInfo) -> ! {
 #[lang = "oom"]
 #[no_mangle]
 pub fn rust_oom(layout: Layout) -> ! {
+	// By the time we get here, the allocator has already tried (and
+	// failed) to grow the heap to satisfy this request, so there really is
+	// no more memory to give. Report the heap's final extents alongside the
+	// failed request to make that diagnosable.
+	let (bottom, top) = ALLOCATOR.extents();
+
 	println!(
-		"[{}][!!!OOM!!!] Memory allocation of {} bytes failed",
+		"[{}][!!!OOM!!!] Memory allocation of {} bytes (align {}) failed; \
+		 heap spans 0x{:x}-0x{:x} ({} KiB) after growth attempts",
 		arch::percore::core_id(),
-		layout.size()
+		layout.size(),
+		layout.align(),
+		bottom,
+		top,
+		(top - bottom) / 1024
 	);
 	loop {
 		arch::processor::halt();
@@ -0,0 +1,287 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+pub mod task;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use arch::percore::{core_scheduler, CoreId};
+use core::sync::atomic::{AtomicU64, Ordering};
+use scheduler::task::{Priority, Task, TaskControlBlock, TaskId, TaskStatus};
+use synch::spinlock::Spinlock;
+
+/// Initializes the scheduler subsystem on the boot core.
+pub fn init() {
+	// Per-core scheduler structures are brought up lazily as each core calls
+	// `add_current_core`; nothing to do globally yet.
+}
+
+/// Brings up the scheduler on the calling core, creating its idle task and
+/// registering it with `arch::percore`.
+pub fn add_current_core() {
+	arch::percore::init_core_scheduler();
+}
+
+/// A process-wide table mapping every live `TaskId` to its control block, so
+/// code holding only an id (e.g. a `RecursiveMutex`'s former owner) can look
+/// the task back up regardless of which core or queue it currently lives on.
+static TASK_TABLE: Spinlock<BTreeMap<TaskId, Task>> = Spinlock::new(BTreeMap::new());
+
+pub fn register_task(task: Task) {
+	let id = task.lock().id;
+	TASK_TABLE.lock().insert(id, task);
+}
+
+pub fn deregister_task(id: TaskId) {
+	TASK_TABLE.lock().remove(&id);
+}
+
+pub fn get_owning_task(id: TaskId) -> Option<Task> {
+	TASK_TABLE.lock().get(&id).cloned()
+}
+
+/// Monotonic tick counter, advanced by the architecture's timer interrupt
+/// handler. Used to time out blocked tasks without having to consult a
+/// full wall-clock source.
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn get_timer_ticks() -> u64 {
+	TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+pub fn advance_timer_ticks(delta: u64) {
+	TIMER_TICKS.fetch_add(delta, Ordering::Relaxed);
+}
+
+/// A queue of tasks that are blocked, optionally with a wakeup deadline
+/// (in `TIMER_TICKS` units) at which they should be resumed even if nobody
+/// calls `custom_wakeup` on them first.
+pub struct BlockedTaskQueue {
+	tasks: alloc::vec::Vec<(Task, Option<u64>)>,
+}
+
+impl BlockedTaskQueue {
+	pub const fn new() -> Self {
+		Self {
+			tasks: alloc::vec::Vec::new(),
+		}
+	}
+
+	pub fn add(&mut self, task: Task, wakeup_time: Option<u64>) {
+		task.lock().status = TaskStatus::TaskBlocked;
+		self.tasks.push((task, wakeup_time));
+
+		// Tickless wakeups: rather than a periodic tick, we (re-)program a
+		// one-shot timer for the earliest deadline we now know about.
+		if wakeup_time.is_some() {
+			if let Some(earliest) = self.earliest_deadline() {
+				arch::set_oneshot_timer(earliest);
+			}
+		}
+	}
+
+	fn earliest_deadline(&self) -> Option<u64> {
+		self.tasks.iter().filter_map(|(_, t)| *t).min()
+	}
+
+	pub fn custom_wakeup(&mut self, task: Task) {
+		let id = task.lock().id;
+		self.tasks.retain(|(t, _)| t.lock().id != id);
+		wakeup_task(task);
+	}
+
+	/// Called from the timer interrupt handler: wakes every task whose
+	/// deadline has passed and returns the earliest remaining deadline (if
+	/// any), so the caller can reprogram a tickless one-shot timer.
+	///
+	/// A timed-out task is only removed from *this* generic queue here --
+	/// it is still parked in whatever `RecursiveMutex`/futex wait queue it
+	/// blocked on, and that primitive is responsible for noticing the
+	/// timeout and removing it itself once it wakes up (see
+	/// `RecursiveMutex::acquire_timeout`). If `release`/`futex_wake` on
+	/// another core pops the same task from that primitive's queue before
+	/// it gets a chance to do so, it would try to wake it a second time;
+	/// `wakeup_task` guards against that by only acting on a still-blocked
+	/// task, so the loser of that race is a harmless no-op instead of a
+	/// double enqueue onto `ready_queue`.
+	pub fn handle_timeouts(&mut self, now: u64) -> Option<u64> {
+		let mut next_deadline = None;
+		let mut i = 0;
+
+		while i < self.tasks.len() {
+			match self.tasks[i].1 {
+				Some(deadline) if deadline <= now => {
+					let (task, _) = self.tasks.remove(i);
+					wakeup_task(task);
+				}
+				Some(deadline) => {
+					next_deadline = Some(match next_deadline {
+						Some(current) if current <= deadline => current,
+						_ => deadline,
+					});
+					i += 1;
+				}
+				None => i += 1,
+			}
+		}
+
+		next_deadline
+	}
+}
+
+/// Transitions `task` from blocked to ready and enqueues it, unless it has
+/// already been woken by a racing caller (e.g. a timeout and a concurrent
+/// `release`/`futex_wake` both targeting the same task). Idempotent: only
+/// the first caller to observe `TaskBlocked` does anything, so a second,
+/// redundant wakeup is a no-op rather than enqueuing the same task twice.
+fn wakeup_task(task: Task) {
+	let was_blocked = {
+		let mut tcb = task.lock();
+		if tcb.status != TaskStatus::TaskBlocked {
+			false
+		} else {
+			tcb.status = TaskStatus::TaskReady;
+			true
+		}
+	};
+
+	if !was_blocked {
+		return;
+	}
+
+	let core_id = task.lock().core_id;
+	get_scheduler(core_id)
+		.ready_queue
+		.lock()
+		.push(task);
+}
+
+pub struct PerCoreScheduler {
+	pub current_task: Task,
+	pub ready_queue: Spinlock<task::PriorityTaskQueue>,
+	pub blocked_tasks: Spinlock<BlockedTaskQueue>,
+}
+
+impl PerCoreScheduler {
+	/// Picks the next ready task (if any) and switches to it. Architecture
+	/// context-switch details live in `arch`; this only handles the
+	/// scheduler-level bookkeeping.
+	pub fn scheduler(&self) {
+		if let Some(next) = self.ready_queue.lock().pop() {
+			next.lock().status = TaskStatus::TaskRunning;
+			arch::switch_to_task(next);
+		}
+	}
+
+	pub fn spawn(
+		&self,
+		func: extern "C" fn(usize),
+		arg: usize,
+		prio: Priority,
+	) -> TaskId {
+		let id = TaskId::from(arch::get_new_tid());
+		let core_id = self.current_task.lock().core_id;
+		let task = Arc::new(Spinlock::new(TaskControlBlock::new(id, core_id, prio)));
+		arch::create_task(task.clone(), func, arg);
+		register_task(task.clone());
+		self.ready_queue.lock().push(task);
+		id
+	}
+
+	/// Terminates the currently running task: runs its registered TLS
+	/// destructors, marks it finished, drops it from the global task table
+	/// so no stale `TaskId` can be looked up again, and switches away for
+	/// good (the task control block itself stays alive as long as other
+	/// `Task` handles, e.g. a join waiter, still reference it).
+	pub fn exit(&self) -> ! {
+		let task = self.current_task.clone();
+		task::run_dtors(&task);
+
+		task.lock().status = TaskStatus::TaskFinished;
+		deregister_task(task.lock().id);
+
+		loop {
+			self.scheduler();
+		}
+	}
+
+	/// Re-sorts `task` into this core's ready queue at its current priority,
+	/// if it is actually runnable. Used after a priority-inheritance boost
+	/// or restoration changes a task's effective priority while it sits in
+	/// the ready queue.
+	pub fn reinsert_if_runnable(&self, task: Task) {
+		if task.lock().status != TaskStatus::TaskReady {
+			return;
+		}
+
+		let id = task.lock().id;
+		let mut ready_queue = self.ready_queue.lock();
+		if ready_queue.remove(id) {
+			ready_queue.push(task);
+		}
+	}
+}
+
+pub fn get_scheduler(core_id: CoreId) -> &'static PerCoreScheduler {
+	arch::percore::get_scheduler(core_id)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn make_blocked_task(id: u32) -> Task {
+		let task = Arc::new(Spinlock::new(TaskControlBlock::new(
+			TaskId::from(id),
+			0,
+			task::NORMAL_PRIO,
+		)));
+		task.lock().status = TaskStatus::TaskBlocked;
+		task
+	}
+
+	#[test]
+	fn handle_timeouts_wakes_only_expired_tasks() {
+		let t_expired = make_blocked_task(1);
+		let t_pending = make_blocked_task(2);
+		let t_no_deadline = make_blocked_task(3);
+
+		let mut queue = BlockedTaskQueue {
+			tasks: alloc::vec![
+				(t_expired.clone(), Some(50)),
+				(t_pending.clone(), Some(100)),
+				(t_no_deadline.clone(), None),
+			],
+		};
+
+		let next_deadline = queue.handle_timeouts(75);
+
+		assert_eq!(t_expired.lock().status, TaskStatus::TaskReady);
+		assert_eq!(t_pending.lock().status, TaskStatus::TaskBlocked);
+		assert_eq!(t_no_deadline.lock().status, TaskStatus::TaskBlocked);
+		assert_eq!(next_deadline, Some(100));
+		assert_eq!(queue.tasks.len(), 2);
+	}
+
+	#[test]
+	fn handle_timeouts_reports_no_deadline_once_the_queue_is_empty() {
+		let mut queue = BlockedTaskQueue { tasks: alloc::vec![] };
+		assert_eq!(queue.handle_timeouts(0), None);
+	}
+}
+
+/// Invoked from the architecture's one-shot timer interrupt. Wakes every
+/// task on this core whose deadline has passed and, if any deadlines
+/// remain, reprograms the timer for the next one.
+pub fn timer_interrupt_handler() {
+	let now = get_timer_ticks();
+	let next_deadline = core_scheduler().blocked_tasks.lock().handle_timeouts(now);
+
+	if let Some(deadline) = next_deadline {
+		arch::set_oneshot_timer(deadline);
+	}
+}
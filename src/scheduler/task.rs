@@ -0,0 +1,414 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use arch::percore::CoreId;
+use core::fmt;
+use synch::spinlock::Spinlock;
+
+pub type Tid = u32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct TaskId(Tid);
+
+impl TaskId {
+	pub const fn from(x: Tid) -> Self {
+		TaskId(x)
+	}
+
+	pub const fn into(self) -> Tid {
+		self.0
+	}
+}
+
+impl fmt::Display for TaskId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+pub type Priority = u8;
+
+pub const IDLE_PRIO: Priority = 0;
+pub const LOW_PRIO: Priority = 1;
+pub const NORMAL_PRIO: Priority = 2;
+pub const HIGH_PRIO: Priority = 3;
+pub const NO_PRIORITIES: usize = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TaskStatus {
+	TaskReady,
+	TaskRunning,
+	TaskBlocked,
+	TaskFinished,
+}
+
+/// A task owned jointly by whatever cores currently hold a handle to it
+/// (its own core's scheduler, `TASK_TABLE`, another core's `ready_queue` or
+/// wait queue while it's being woken cross-core, ...). `Rc<RefCell<_>>`
+/// would not do here: its refcount and borrow flag are plain, non-atomic
+/// counters, and a `Task` routinely crosses core boundaries (e.g.
+/// `futex_wake`/`RecursiveMutex::release` pushing a woken task onto a
+/// *different* core's `ready_queue`, or a priority boost reaching into a
+/// `Task` owned by another core via `get_owning_task`). `Arc` gives atomic
+/// refcounting and `Spinlock` gives real mutual exclusion on the data
+/// itself, which is what concurrent access from multiple cores actually
+/// requires.
+pub type Task = Arc<Spinlock<TaskControlBlock>>;
+
+/// Implemented by blocking primitives (currently only `RecursiveMutex`)
+/// that want to participate in priority inheritance: they just need to be
+/// able to report the priority of their highest-priority waiter.
+pub trait PriorityInheriting {
+	fn highest_waiter_priority(&self) -> Option<Priority>;
+}
+
+/// Implemented by blocking primitives whose wait queue is a
+/// `PriorityTaskQueue` (currently only `RecursiveMutex`). A task already
+/// parked in such a queue is bucketed by the priority it had at `push` time;
+/// if it is later boosted (because a *different* mutex it holds gains a
+/// higher-priority waiter), the queue it's actually sitting in has no way to
+/// notice and re-bucket it on its own. `requeue` lets whoever boosted the
+/// task tell its wait queue to re-sort it, mirroring what
+/// `PerCoreScheduler::reinsert_if_runnable` does for the ready queue.
+pub trait Requeueable {
+	fn requeue(&self, task: Task);
+}
+
+pub struct TaskControlBlock {
+	pub id: TaskId,
+	pub core_id: CoreId,
+	pub status: TaskStatus,
+
+	/// The priority this task was created with. Priority inheritance never
+	/// lowers a task below this floor.
+	pub base_prio: Priority,
+
+	/// The priority this task is currently scheduled with. Equal to
+	/// `base_prio` unless boosted by `[[RecursiveMutex]]` priority
+	/// inheritance, in which case it is `max(base_prio, highest waiter
+	/// across all mutexes held by this task)`.
+	pub prio: Priority,
+
+	/// Mutexes currently held by this task, most-recently-acquired last.
+	/// Used to recompute `prio` when a mutex is released or a waiter on one
+	/// of these mutexes leaves.
+	pub held_mutexes: Vec<&'static dyn PriorityInheriting>,
+
+	/// Thread-local destructors registered via `sys_thread_local_dtor`, in
+	/// registration order. Run in reverse once this task exits.
+	pub tls_dtors: Vec<(*mut u8, unsafe extern "C" fn(*mut u8))>,
+
+	/// The wait queue this task is currently parked in, if it is blocked on a
+	/// `Requeueable` primitive. Lets a priority boost reaching this task
+	/// (via `recompute_effective_priority_excluding`) ask that queue to
+	/// re-bucket it, instead of leaving it stuck under its stale priority.
+	pub waiting_on: Option<&'static dyn Requeueable>,
+}
+
+impl TaskControlBlock {
+	pub fn new(id: TaskId, core_id: CoreId, prio: Priority) -> Self {
+		Self {
+			id,
+			core_id,
+			status: TaskStatus::TaskReady,
+			base_prio: prio,
+			prio,
+			held_mutexes: Vec::new(),
+			tls_dtors: Vec::new(),
+			waiting_on: None,
+		}
+	}
+
+	pub fn register_tls_dtor(&mut self, t: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
+		self.tls_dtors.push((t, dtor));
+	}
+
+	/// Recomputes this task's effective priority as the maximum of its base
+	/// priority and the highest-priority waiter across every mutex it still
+	/// holds, per the priority-inheritance invariant.
+	pub fn recompute_effective_priority(&mut self) -> Priority {
+		self.recompute_effective_priority_excluding(core::ptr::null(), None)
+	}
+
+	/// Like [`recompute_effective_priority`](Self::recompute_effective_priority),
+	/// but for the held mutex whose data pointer equals `exclude` (if any),
+	/// uses `known_priority` instead of calling
+	/// `PriorityInheriting::highest_waiter_priority` on it.
+	///
+	/// This exists so a mutex that is boosting its own owner while a waiter
+	/// is still holding that very mutex's internal lock can supply the
+	/// waiter priority it already knows, rather than have this function
+	/// call back into the mutex and re-lock something the caller's stack is
+	/// still holding.
+	pub fn recompute_effective_priority_excluding(
+		&mut self,
+		exclude: *const (),
+		known_priority: Option<Priority>,
+	) -> Priority {
+		let inherited = self
+			.held_mutexes
+			.iter()
+			.filter_map(|m| {
+				let ptr = *m as *const dyn PriorityInheriting as *const ();
+				if !exclude.is_null() && ptr == exclude {
+					known_priority
+				} else {
+					m.highest_waiter_priority()
+				}
+			})
+			.max()
+			.unwrap_or(self.base_prio);
+
+		self.prio = core::cmp::max(self.base_prio, inherited);
+		self.prio
+	}
+}
+
+// `held_mutexes` and `waiting_on` store `&'static dyn PriorityInheriting`/
+// `&'static dyn Requeueable` references, whose only current implementor
+// (`RecursiveMutex`) is already manually `Sync`, and `tls_dtors` stores raw
+// pointers that are exclusively owned by this task and never dereferenced by
+// anyone else. Moving a `TaskControlBlock` to another core is sound as long
+// as access to it is mutually exclusive, which
+// `Task = Arc<Spinlock<TaskControlBlock>>` already guarantees.
+unsafe impl Send for TaskControlBlock {}
+
+/// Runs and drains `task`'s registered TLS destructors in reverse
+/// registration order, just before the task is reaped. A destructor may
+/// register further keys (as `std`'s TLS implementation does), so we keep
+/// draining until a round adds nothing new -- bounded by `MAX_ROUNDS` so a
+/// destructor that keeps re-registering itself can't hang task exit forever.
+pub fn run_dtors(task: &Task) {
+	const MAX_ROUNDS: usize = 8;
+
+	for _ in 0..MAX_ROUNDS {
+		let dtors = core::mem::replace(&mut task.lock().tls_dtors, Vec::new());
+		if dtors.is_empty() {
+			break;
+		}
+
+		for (ptr, dtor) in dtors.into_iter().rev() {
+			unsafe {
+				dtor(ptr);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod run_dtors_tests {
+	use super::*;
+	use core::sync::atomic::{AtomicUsize, Ordering};
+
+	static NEXT_ORDER: AtomicUsize = AtomicUsize::new(0);
+
+	unsafe extern "C" fn record_order(ptr: *mut u8) {
+		let slot = &*(ptr as *const AtomicUsize);
+		slot.store(NEXT_ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+	}
+
+	#[test]
+	fn runs_destructors_in_reverse_registration_order() {
+		NEXT_ORDER.store(0, Ordering::SeqCst);
+		let task: Task = Arc::new(Spinlock::new(TaskControlBlock::new(
+			TaskId::from(1),
+			0,
+			NORMAL_PRIO,
+		)));
+
+		let slot_a = AtomicUsize::new(usize::max_value());
+		let slot_b = AtomicUsize::new(usize::max_value());
+		let slot_c = AtomicUsize::new(usize::max_value());
+
+		task.lock()
+			.register_tls_dtor(&slot_a as *const _ as *mut u8, record_order);
+		task.lock()
+			.register_tls_dtor(&slot_b as *const _ as *mut u8, record_order);
+		task.lock()
+			.register_tls_dtor(&slot_c as *const _ as *mut u8, record_order);
+
+		run_dtors(&task);
+
+		// Last registered, first run.
+		assert_eq!(slot_c.load(Ordering::SeqCst), 0);
+		assert_eq!(slot_b.load(Ordering::SeqCst), 1);
+		assert_eq!(slot_a.load(Ordering::SeqCst), 2);
+		assert!(task.lock().tls_dtors.is_empty());
+	}
+
+	unsafe extern "C" fn reregister(ptr: *mut u8) {
+		NEXT_ORDER.fetch_add(1, Ordering::SeqCst);
+		let task = &*(ptr as *const Task);
+		task.lock().register_tls_dtor(ptr, reregister);
+	}
+
+	#[test]
+	fn stops_after_a_bounded_number_of_rounds_even_if_a_destructor_keeps_reregistering() {
+		NEXT_ORDER.store(0, Ordering::SeqCst);
+		let task: Task = Arc::new(Spinlock::new(TaskControlBlock::new(
+			TaskId::from(2),
+			0,
+			NORMAL_PRIO,
+		)));
+		let ptr = &task as *const Task as *mut u8;
+		task.lock().register_tls_dtor(ptr, reregister);
+
+		run_dtors(&task);
+
+		// Each of the 8 rounds runs exactly one destructor call, which
+		// immediately re-registers itself for the next round; the loop must
+		// still terminate instead of spinning on this forever.
+		assert_eq!(NEXT_ORDER.load(Ordering::SeqCst), 8);
+		assert_eq!(task.lock().tls_dtors.len(), 1);
+	}
+}
+
+/// A run queue ordered by priority, implemented as one `VecDeque` per
+/// priority level plus a bitmap of the non-empty levels so `pop` doesn't
+/// have to scan all of them.
+pub struct PriorityTaskQueue {
+	queues: [VecDeque<Task>; NO_PRIORITIES],
+	prio_bitmap: u64,
+}
+
+impl PriorityTaskQueue {
+	pub const fn new() -> Self {
+		Self {
+			queues: [
+				VecDeque::new(),
+				VecDeque::new(),
+				VecDeque::new(),
+				VecDeque::new(),
+			],
+			prio_bitmap: 0,
+		}
+	}
+
+	pub fn push(&mut self, task: Task) {
+		let prio = task.lock().prio as usize;
+		self.prio_bitmap |= 1 << prio;
+		self.queues[prio].push_back(task);
+	}
+
+	/// Removes and returns the highest-priority task in the queue.
+	pub fn pop(&mut self) -> Option<Task> {
+		let prio = self.highest_priority()? as usize;
+		let task = self.queues[prio].pop_front();
+		if self.queues[prio].is_empty() {
+			self.prio_bitmap &= !(1 << prio);
+		}
+
+		task
+	}
+
+	/// Removes the task with the given id, wherever it is parked in the
+	/// queue, and reports whether it was found.
+	pub fn remove(&mut self, id: TaskId) -> bool {
+		for prio in 0..NO_PRIORITIES {
+			if let Some(pos) = self.queues[prio].iter().position(|t| t.lock().id == id) {
+				self.queues[prio].remove(pos);
+				if self.queues[prio].is_empty() {
+					self.prio_bitmap &= !(1 << prio);
+				}
+				return true;
+			}
+		}
+
+		false
+	}
+
+	/// Re-buckets `task` at its current priority, if it is actually parked
+	/// somewhere in this queue. Used when a task already waiting here is
+	/// boosted (or restored) after it was enqueued, so its bucket doesn't go
+	/// stale -- see [`Requeueable`].
+	pub fn reinsert(&mut self, task: Task) {
+		let id = task.lock().id;
+		if self.remove(id) {
+			self.push(task);
+		}
+	}
+
+	/// The priority of the highest-priority waiter currently enqueued, if
+	/// any. Used by owners of a blocking primitive to compute the priority
+	/// they should inherit from their waiters.
+	pub fn highest_priority(&self) -> Option<Priority> {
+		if self.prio_bitmap == 0 {
+			None
+		} else {
+			Some(63 - self.prio_bitmap.leading_zeros() as Priority)
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.prio_bitmap == 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn make_task(id: u32, prio: Priority) -> Task {
+		Arc::new(Spinlock::new(TaskControlBlock::new(TaskId::from(id), 0, prio)))
+	}
+
+	#[test]
+	fn pop_returns_highest_priority_first() {
+		let mut queue = PriorityTaskQueue::new();
+		queue.push(make_task(1, LOW_PRIO));
+		queue.push(make_task(2, HIGH_PRIO));
+		queue.push(make_task(3, NORMAL_PRIO));
+
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(2));
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(3));
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(1));
+		assert!(queue.pop().is_none());
+	}
+
+	#[test]
+	fn pop_preserves_fifo_order_within_a_priority() {
+		let mut queue = PriorityTaskQueue::new();
+		queue.push(make_task(1, NORMAL_PRIO));
+		queue.push(make_task(2, NORMAL_PRIO));
+
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(1));
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(2));
+	}
+
+	#[test]
+	fn remove_finds_task_at_any_priority_and_is_idempotent() {
+		let mut queue = PriorityTaskQueue::new();
+		queue.push(make_task(1, LOW_PRIO));
+		queue.push(make_task(2, HIGH_PRIO));
+
+		assert!(queue.remove(TaskId::from(2)));
+		assert!(!queue.remove(TaskId::from(2)));
+		assert_eq!(queue.pop().unwrap().lock().id, TaskId::from(1));
+	}
+
+	#[test]
+	fn highest_priority_tracks_the_bitmap_as_tasks_come_and_go() {
+		let mut queue = PriorityTaskQueue::new();
+		assert_eq!(queue.highest_priority(), None);
+		assert!(queue.is_empty());
+
+		queue.push(make_task(1, LOW_PRIO));
+		assert_eq!(queue.highest_priority(), Some(LOW_PRIO));
+
+		queue.push(make_task(2, HIGH_PRIO));
+		assert_eq!(queue.highest_priority(), Some(HIGH_PRIO));
+
+		queue.pop();
+		assert_eq!(queue.highest_priority(), Some(LOW_PRIO));
+
+		queue.pop();
+		assert!(queue.is_empty());
+	}
+}
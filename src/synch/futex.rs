@@ -0,0 +1,137 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A small futex subsystem, modeled after the Linux `futex(2)` syscall, that
+//! gives higher-level code (e.g. a `std` port built on top of this kernel) a
+//! way to park and wake tasks on an arbitrary 32-bit word instead of having
+//! to build yet another bespoke blocking primitive.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use arch::mm::paging;
+use arch::percore::*;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use errno::ETIMEDOUT;
+use scheduler;
+use scheduler::task::PriorityTaskQueue;
+use synch::spinlock::Spinlock;
+
+struct FutexBucket {
+	queue: PriorityTaskQueue,
+}
+
+impl FutexBucket {
+	const fn new() -> Self {
+		Self {
+			queue: PriorityTaskQueue::new(),
+		}
+	}
+}
+
+static FUTEX_TABLE: Spinlock<BTreeMap<usize, FutexBucket>> = Spinlock::new(BTreeMap::new());
+
+/// Translates the futex word's address into the key we bucket on.
+///
+/// We deliberately key on the physical address instead of the virtual one:
+/// the same physical page can be mapped at different virtual addresses in
+/// different address spaces, and futexes are only meaningful when waiters
+/// agree on the memory location they refer to.
+fn futex_key(addr: *const u32) -> usize {
+	paging::virt_to_phys(addr as usize)
+}
+
+/// Parks the current task on `addr` if `*addr == expected`, and returns once
+/// it has been woken up (spuriously or not) or `timeout` has elapsed.
+///
+/// Returns `0` on a (possibly spurious) wakeup and `-ETIMEDOUT` if `timeout`
+/// expired first. The caller is responsible for re-checking `*addr` after a
+/// successful return, since a wakeup does not guarantee the value changed.
+pub fn futex_wait(addr: *const u32, expected: u32, timeout: Option<Duration>) -> i32 {
+	let key = futex_key(addr);
+	let core_scheduler = core_scheduler();
+	let wakeup_time = timeout.map(|d| scheduler::get_timer_ticks() + d.as_nanos() as u64);
+
+	{
+		let mut table = FUTEX_TABLE.lock();
+
+		// Atomically check that the futex word still holds the value we expect.
+		// This has to happen under the table lock so a concurrent `futex_wake`
+		// cannot sneak in between the check and the wait.
+		let current = unsafe { (*(addr as *const AtomicU32)).load(Ordering::SeqCst) };
+		if current != expected {
+			return 0;
+		}
+
+		let bucket = table.entry(key).or_insert_with(FutexBucket::new);
+		core_scheduler
+			.blocked_tasks
+			.lock()
+			.add(core_scheduler.current_task.clone(), wakeup_time);
+		bucket.queue.push(core_scheduler.current_task.clone());
+	}
+
+	// Drop the table lock before switching tasks, we don't want to hold it
+	// across a context switch.
+	core_scheduler.scheduler();
+
+	// We get here either because `futex_wake` popped us off the queue, or
+	// because our deadline passed and the timer interrupt woke us up while
+	// we were still parked. Tell them apart by checking whether we are still
+	// enqueued in the bucket.
+	if wakeup_time.is_some() {
+		let mut table = FUTEX_TABLE.lock();
+		if let Some(bucket) = table.get_mut(&key) {
+			let tid = core_scheduler.current_task.lock().id;
+			if bucket.queue.remove(tid) {
+				if bucket.queue.is_empty() {
+					table.remove(&key);
+				}
+
+				return -ETIMEDOUT;
+			}
+		}
+	}
+
+	0
+}
+
+/// Wakes up to `count` tasks waiting on `addr`. Passing `i32::MAX` wakes
+/// every waiter currently parked on this futex. Returns the number of tasks
+/// that were actually woken.
+pub fn futex_wake(addr: *const u32, count: i32) -> i32 {
+	let key = futex_key(addr);
+	let mut woken = 0;
+	let mut woken_tasks = Vec::new();
+
+	{
+		let mut table = FUTEX_TABLE.lock();
+		if let Some(bucket) = table.get_mut(&key) {
+			while (count == i32::max_value() || woken < count) && {
+				if let Some(task) = bucket.queue.pop() {
+					woken_tasks.push(task);
+					true
+				} else {
+					false
+				}
+			} {
+				woken += 1;
+			}
+
+			if bucket.queue.is_empty() {
+				table.remove(&key);
+			}
+		}
+	}
+
+	for task in woken_tasks {
+		let core_scheduler = scheduler::get_scheduler(task.lock().core_id);
+		core_scheduler.blocked_tasks.lock().custom_wakeup(task);
+	}
+
+	woken
+}
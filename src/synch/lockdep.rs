@@ -0,0 +1,237 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An optional lock-order validator ("lockdep"), modeled loosely after
+//! Linux's `CONFIG_PROVE_LOCKING`. Enabled with `--features lock_validator`,
+//! it tracks, per core, the stack of locks currently held and a global
+//! "acquired-before" graph across all `Spinlock`/`RecursiveMutex` instances.
+//! If taking a new lock would close a cycle in that graph, two code paths
+//! could deadlock each other by acquiring the same two locks in opposite
+//! order -- so we print the offending classes and the current hold stack
+//! and panic immediately, instead of waiting for the deadlock to actually
+//! happen at runtime.
+//!
+//! This whole module only exists when the `lock_validator` feature is on;
+//! with it off, none of this code is even compiled in.
+#![cfg(feature = "lock_validator")]
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use arch::percore::core_id;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// We use the lock's own address as its class id. Since every
+/// `Spinlock`/`RecursiveMutex` instrumented here is a `'static`, this is
+/// stable for the program's entire lifetime and -- unlike a counter handed
+/// out in the constructor -- needs no bookkeeping at `const fn new()` time,
+/// so locks can keep being declared as plain `static` items.
+pub type LockClassId = usize;
+
+/// A minimal spinlock used only by the validator itself. Deliberately not
+/// the instrumented `synch::spinlock::Spinlock`, since that would recurse
+/// right back into this module.
+struct RawLock<T> {
+	locked: AtomicBool,
+	data: core::cell::UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for RawLock<T> {}
+
+struct RawLockGuard<'a, T: 'a> {
+	lock: &'a RawLock<T>,
+}
+
+impl<T> RawLock<T> {
+	const fn new(data: T) -> Self {
+		Self {
+			locked: AtomicBool::new(false),
+			data: core::cell::UnsafeCell::new(data),
+		}
+	}
+
+	fn lock(&self) -> RawLockGuard<T> {
+		while self
+			.locked
+			.compare_and_swap(false, true, Ordering::Acquire)
+		{
+			core::hint::spin_loop();
+		}
+		RawLockGuard { lock: self }
+	}
+}
+
+impl<'a, T> core::ops::Deref for RawLockGuard<'a, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe { &*self.lock.data.get() }
+	}
+}
+
+impl<'a, T> core::ops::DerefMut for RawLockGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.lock.data.get() }
+	}
+}
+
+impl<'a, T> Drop for RawLockGuard<'a, T> {
+	fn drop(&mut self) {
+		self.lock.locked.store(false, Ordering::Release);
+	}
+}
+
+/// The global "acquired-before" graph: an edge `a -> b` means some core has
+/// been observed holding lock class `a` while acquiring lock class `b`.
+static GRAPH: RawLock<BTreeMap<LockClassId, BTreeSet<LockClassId>>> = RawLock::new(BTreeMap::new());
+
+/// Per-core stack of lock classes currently held, outermost first.
+static HOLD_STACKS: RawLock<BTreeMap<usize, Vec<LockClassId>>> = RawLock::new(BTreeMap::new());
+
+/// Returns `true` if `graph` already contains a path from `from` to `to`.
+fn path_exists(
+	graph: &BTreeMap<LockClassId, BTreeSet<LockClassId>>,
+	from: LockClassId,
+	to: LockClassId,
+) -> bool {
+	let mut stack = vec![from];
+	let mut visited = BTreeSet::new();
+
+	while let Some(node) = stack.pop() {
+		if node == to {
+			return true;
+		}
+
+		if !visited.insert(node) {
+			continue;
+		}
+
+		if let Some(successors) = graph.get(&node) {
+			stack.extend(successors.iter().copied());
+		}
+	}
+
+	false
+}
+
+/// Called just before a lock of class `class` is actually acquired.
+/// `recursive_capable` must be `true` only for classes that know how to
+/// nest -- in this kernel, that's `RecursiveMutex`'s own logical class,
+/// which only ever calls this once per outer acquisition (recursive
+/// re-acquires by the same task take a fast path that never reaches here,
+/// see `RecursiveMutex::acquire`). For everything else, in particular every
+/// plain `Spinlock`, re-acquiring the same class on the same core's hold
+/// stack is not recursion -- it's the classic single-thread self-deadlock,
+/// since a ticket `Spinlock` cannot be locked twice by the same call stack
+/// and will spin against itself forever.
+///
+/// Records an edge from every class this core currently holds to `class`,
+/// and panics if doing so would close a cycle (i.e. `class` can already
+/// reach some held class, which combined with the new `held -> class` edge
+/// means `class` and that held lock can be acquired in either order by
+/// different code paths).
+pub fn before_acquire(class: LockClassId, recursive_capable: bool) {
+	let this_core = core_id() as usize;
+	let mut stacks = HOLD_STACKS.lock();
+	let stack = stacks.entry(this_core).or_insert_with(Vec::new);
+
+	{
+		let mut graph = GRAPH.lock();
+		for &held in stack.iter() {
+			if held == class {
+				if recursive_capable {
+					// Recursive acquisition of the very same lock instance;
+					// not an ordering violation by itself.
+					continue;
+				}
+
+				println!(
+					"[lockdep] self-deadlock: lock class {} is already held on core {}'s \
+					 stack and is not recursive-capable",
+					class, this_core
+				);
+				println!("[lockdep] current hold stack on core {}: {:?}", this_core, stack);
+				panic!("lock order violation detected");
+			}
+
+			if path_exists(&graph, class, held) {
+				println!(
+					"[lockdep] potential deadlock: lock class {} is acquired after {}, \
+					 but {} is already known to be acquired after {}",
+					class, held, held, class
+				);
+				println!("[lockdep] current hold stack on core {}: {:?}", this_core, stack);
+				panic!("lock order violation detected");
+			}
+
+			graph.entry(held).or_insert_with(BTreeSet::new).insert(class);
+		}
+	}
+
+	stack.push(class);
+}
+
+/// Called just after a lock of class `class` has been released. Pops it
+/// off this core's hold stack.
+///
+/// Locks usually unwind in LIFO order, but not always: `RecursiveMutex`
+/// pushes its own class while its internal state `Spinlock` is held and
+/// then releases that `Spinlock` first. If `class` isn't on top, it is
+/// removed from wherever it sits in the stack instead of panicking.
+pub fn after_release(class: LockClassId) {
+	let this_core = core_id() as usize;
+	let mut stacks = HOLD_STACKS.lock();
+
+	if let Some(stack) = stacks.get_mut(&this_core) {
+		if stack.last() == Some(&class) {
+			stack.pop();
+		} else if let Some(pos) = stack.iter().rposition(|&c| c == class) {
+			stack.remove(pos);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn edge(graph: &mut BTreeMap<LockClassId, BTreeSet<LockClassId>>, from: LockClassId, to: LockClassId) {
+		graph.entry(from).or_insert_with(BTreeSet::new).insert(to);
+	}
+
+	#[test]
+	fn finds_direct_and_transitive_paths() {
+		let mut graph = BTreeMap::new();
+		edge(&mut graph, 1, 2);
+		edge(&mut graph, 2, 3);
+
+		assert!(path_exists(&graph, 1, 2));
+		assert!(path_exists(&graph, 1, 3));
+		assert!(!path_exists(&graph, 3, 1));
+	}
+
+	#[test]
+	fn reports_no_path_between_disconnected_classes() {
+		let mut graph = BTreeMap::new();
+		edge(&mut graph, 1, 2);
+		edge(&mut graph, 10, 20);
+
+		assert!(!path_exists(&graph, 1, 20));
+		assert!(!path_exists(&graph, 20, 1));
+	}
+
+	#[test]
+	fn terminates_on_a_cyclic_graph_instead_of_looping_forever() {
+		let mut graph = BTreeMap::new();
+		edge(&mut graph, 1, 2);
+		edge(&mut graph, 2, 1);
+		edge(&mut graph, 2, 3);
+
+		assert!(path_exists(&graph, 1, 3));
+		// 3 has no outgoing edges, so this terminates instead of looping
+		// around the 1 <-> 2 cycle forever.
+		assert!(!path_exists(&graph, 3, 1));
+	}
+}
@@ -0,0 +1,12 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+pub mod futex;
+#[cfg(feature = "lock_validator")]
+pub mod lockdep;
+pub mod recmutex;
+pub mod spinlock;
@@ -6,8 +6,11 @@
 // copied, modified, or distributed except according to those terms.
 
 use arch::percore::*;
+use core::time::Duration;
 use scheduler;
-use scheduler::task::{PriorityTaskQueue, TaskId};
+use scheduler::task::{PriorityInheriting, PriorityTaskQueue, Requeueable, TaskId};
+#[cfg(feature = "lock_validator")]
+use synch::lockdep;
 use synch::spinlock::Spinlock;
 
 struct RecursiveMutexState {
@@ -31,10 +34,10 @@ impl RecursiveMutex {
 		}
 	}
 
-	pub fn acquire(&self) {
+	pub fn acquire(&'static self) {
 		// Get information about the current task.
 		let core_scheduler = core_scheduler();
-		let tid = core_scheduler.current_task.borrow().id;
+		let tid = core_scheduler.current_task.lock().id;
 
 		loop {
 			{
@@ -50,8 +53,24 @@ impl RecursiveMutex {
 					}
 				} else {
 					// The mutex is currently not acquired, so we become its new owner.
+					// `recursive_capable: true` here because this *is* the
+					// recursive mutex's own nesting -- but note we never
+					// actually reach this call again for the same task: the
+					// `current_tid == tid` branch above returns early
+					// instead of recursing through lockdep.
+					#[cfg(feature = "lock_validator")]
+					lockdep::before_acquire(
+						self as *const RecursiveMutex as usize,
+						true,
+					);
+
 					locked_state.current_tid = Some(tid);
 					locked_state.count = 1;
+					{
+						let mut owner = core_scheduler.current_task.lock();
+						owner.held_mutexes.push(self);
+						owner.waiting_on = None;
+					}
 					return;
 				}
 
@@ -62,6 +81,12 @@ impl RecursiveMutex {
 					.lock()
 					.add(core_scheduler.current_task.clone(), None);
 				locked_state.queue.push(core_scheduler.current_task.clone());
+				core_scheduler.current_task.lock().waiting_on = Some(self);
+
+				// Boost the owner's effective priority to at least ours, so it
+				// can't be starved by tasks of intermediate priority while we
+				// wait on it (priority inheritance).
+				self.boost_owner(&locked_state);
 			}
 
 			// Switch to the next task.
@@ -69,6 +94,72 @@ impl RecursiveMutex {
 		}
 	}
 
+	/// Like [`acquire`](Self::acquire), but gives up and returns `false` if
+	/// the mutex could not be acquired within `timeout`. Relies on a
+	/// tickless one-shot timer: the blocked task carries a wakeup deadline,
+	/// and the timer interrupt wakes it directly (without going through
+	/// [`release`](Self::release)) once that deadline passes.
+	pub fn acquire_timeout(&'static self, timeout: Duration) -> bool {
+		let core_scheduler = core_scheduler();
+		let tid = core_scheduler.current_task.lock().id;
+		let deadline = scheduler::get_timer_ticks() + timeout.as_nanos() as u64;
+
+		loop {
+			{
+				let mut locked_state = self.state.lock();
+
+				if let Some(current_tid) = locked_state.current_tid {
+					if current_tid == tid {
+						locked_state.count += 1;
+						return true;
+					}
+				} else {
+					#[cfg(feature = "lock_validator")]
+					lockdep::before_acquire(
+						self as *const RecursiveMutex as usize,
+						true,
+					);
+
+					locked_state.current_tid = Some(tid);
+					locked_state.count = 1;
+					{
+						let mut owner = core_scheduler.current_task.lock();
+						owner.held_mutexes.push(self);
+						owner.waiting_on = None;
+					}
+					return true;
+				}
+
+				core_scheduler
+					.blocked_tasks
+					.lock()
+					.add(core_scheduler.current_task.clone(), Some(deadline));
+				locked_state.queue.push(core_scheduler.current_task.clone());
+				core_scheduler.current_task.lock().waiting_on = Some(self);
+				self.boost_owner(&locked_state);
+			}
+
+			core_scheduler.scheduler();
+
+			// Tell a timeout apart from a wakeup via `release`: `release`
+			// already popped us off `queue` before waking us, so if we're
+			// still in it, nobody released the mutex for us in time.
+			let mut locked_state = self.state.lock();
+			if locked_state.queue.remove(tid) {
+				let owner_tid = locked_state.current_tid;
+				drop(locked_state);
+
+				core_scheduler.current_task.lock().waiting_on = None;
+
+				if let Some(owner_tid) = owner_tid {
+					self.recompute_owner_priority(owner_tid);
+				}
+
+				return false;
+			}
+		}
+	}
+
 	pub fn release(&self) {
 		let mut locked_state = self.state.lock();
 
@@ -79,15 +170,131 @@ impl RecursiveMutex {
 		locked_state.count -= 1;
 		if locked_state.count == 0 {
 			// Release the entire recursive mutex.
-			locked_state.current_tid = None;
+			#[cfg(feature = "lock_validator")]
+			lockdep::after_release(self as *const RecursiveMutex as usize);
+
+			let owner_tid = locked_state.current_tid.take();
+
+			// Drop our priority boost: remove this mutex from the former
+			// owner's held list and recompute its effective priority from
+			// whatever it still holds.
+			if let Some(owner_tid) = owner_tid {
+				self.restore_owner_priority(owner_tid);
+			}
 
 			// Wake up any task that has been waiting for this mutex.
 			if let Some(task) = locked_state.queue.pop() {
-				let core_scheduler = scheduler::get_scheduler(task.borrow().core_id);
+				let core_scheduler = scheduler::get_scheduler(task.lock().core_id);
 				core_scheduler.blocked_tasks.lock().custom_wakeup(task);
 			}
 		}
 	}
+
+	/// Boosts the current owner's effective priority to
+	/// `max(base, highest waiter across all held mutexes)` and, if the
+	/// owner is runnable, re-sorts it into the scheduler's ready queue at
+	/// its new priority.
+	///
+	/// Called while `locked_state` (i.e. `self.state`) is still locked by
+	/// the blocking waiter, so we must not let the recompute call back into
+	/// `self.highest_waiter_priority()` -- that would try to re-lock
+	/// `self.state` on the same call stack and spin forever against
+	/// ourselves. We already have the waiter priority it would have
+	/// computed right here (`locked_state.queue.highest_priority()`), so we
+	/// supply it directly instead.
+	fn boost_owner(&self, locked_state: &RecursiveMutexState) {
+		let owner_tid = match locked_state.current_tid {
+			Some(tid) => tid,
+			None => return,
+		};
+
+		let self_ptr = self as *const RecursiveMutex as *const ();
+		let waiter_prio = locked_state.queue.highest_priority();
+		self.recompute_owner_priority_excluding(owner_tid, self_ptr, waiter_prio);
+	}
+
+	/// Recomputes `tid`'s effective priority and, if it changed, re-sorts
+	/// the task into its ready queue so the new priority takes effect
+	/// immediately rather than at the next unrelated reschedule.
+	fn recompute_owner_priority(&self, tid: TaskId) {
+		self.recompute_owner_priority_excluding(tid, core::ptr::null(), None)
+	}
+
+	/// Like [`recompute_owner_priority`](Self::recompute_owner_priority), but
+	/// for the held mutex whose address equals `exclude`, uses
+	/// `known_priority` instead of querying it -- see
+	/// [`boost_owner`](Self::boost_owner) for why that matters.
+	fn recompute_owner_priority_excluding(
+		&self,
+		tid: TaskId,
+		exclude: *const (),
+		known_priority: Option<scheduler::task::Priority>,
+	) {
+		// Every task we might reprioritize is already registered with some
+		// core's scheduler; find it via the global task table if it isn't
+		// the one running on this core.
+		let core_scheduler = core_scheduler();
+		let owner = if core_scheduler.current_task.lock().id == tid {
+			Some(core_scheduler.current_task.clone())
+		} else {
+			scheduler::get_owning_task(tid)
+		};
+
+		if let Some(owner) = owner {
+			let old_prio = owner.lock().prio;
+			let new_prio = owner
+				.lock()
+				.recompute_effective_priority_excluding(exclude, known_priority);
+
+			if new_prio != old_prio {
+				let core_id = owner.lock().core_id;
+				scheduler::get_scheduler(core_id).reinsert_if_runnable(owner.clone());
+
+				// The owner might itself be blocked on another RecursiveMutex
+				// (a nested priority-inheritance chain); that mutex's queue
+				// bucketed it by its now-stale priority, so ask it to
+				// re-sort the owner into its new bucket.
+				let waiting_on = owner.lock().waiting_on;
+				if let Some(waiting_on) = waiting_on {
+					waiting_on.requeue(owner);
+				}
+			}
+		}
+	}
+
+	/// Removes `self` from `tid`'s held-mutexes list and recomputes its
+	/// effective priority from whatever mutexes it still holds, falling back
+	/// to its base priority when it holds none.
+	fn restore_owner_priority(&self, tid: TaskId) {
+		let core_scheduler = core_scheduler();
+		let owner = if core_scheduler.current_task.lock().id == tid {
+			Some(core_scheduler.current_task.clone())
+		} else {
+			scheduler::get_owning_task(tid)
+		};
+
+		if let Some(owner) = owner {
+			let self_ptr = self as *const RecursiveMutex as *const ();
+			owner
+				.lock()
+				.held_mutexes
+				.retain(|m| (*m as *const dyn PriorityInheriting as *const ()) != self_ptr);
+		}
+
+		self.recompute_owner_priority(tid);
+	}
+}
+
+impl PriorityInheriting for RecursiveMutex {
+	fn highest_waiter_priority(&self) -> Option<scheduler::task::Priority> {
+		self.state.lock().queue.highest_priority()
+	}
+}
+
+impl Requeueable for RecursiveMutex {
+	fn requeue(&self, task: scheduler::task::Task) {
+		self.state.lock().queue.reinsert(task);
+	}
 }
 
 // Same unsafe impls as `RecursiveMutex`
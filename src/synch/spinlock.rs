@@ -0,0 +1,98 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "lock_validator")]
+use synch::lockdep;
+
+/// A simple ticket-based spinlock, comparable to `std::sync::Mutex`, but
+/// without the possibility to block the current task. This is required for
+/// synchronizing short, non-blocking critical sections on bare metal, where
+/// no blocking primitives (like `RecursiveMutex`) are available yet.
+pub struct Spinlock<T: ?Sized> {
+	queue: AtomicUsize,
+	dequeue: AtomicUsize,
+	data: UnsafeCell<T>,
+}
+
+pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
+	#[cfg(feature = "lock_validator")]
+	class: lockdep::LockClassId,
+	dequeue: &'a AtomicUsize,
+	data: &'a mut T,
+}
+
+impl<T> Spinlock<T> {
+	pub const fn new(user_data: T) -> Spinlock<T> {
+		Spinlock {
+			queue: AtomicUsize::new(0),
+			dequeue: AtomicUsize::new(1),
+			data: UnsafeCell::new(user_data),
+		}
+	}
+}
+
+impl<T: ?Sized> Spinlock<T> {
+	#[inline]
+	fn obtain_lock(&self) {
+		let ticket = self.queue.fetch_add(1, Ordering::SeqCst) + 1;
+		while self.dequeue.load(Ordering::SeqCst) != ticket {
+			core::hint::spin_loop();
+		}
+	}
+
+	#[inline]
+	pub fn lock(&self) -> SpinlockGuard<T> {
+		// A plain Spinlock is never recursive-capable: locking it twice on
+		// the same stack is always a self-deadlock, not legitimate nesting.
+		#[cfg(feature = "lock_validator")]
+		lockdep::before_acquire(self as *const _ as *const () as usize, false);
+
+		self.obtain_lock();
+		SpinlockGuard {
+			#[cfg(feature = "lock_validator")]
+			class: self as *const _ as *const () as usize,
+			dequeue: &self.dequeue,
+			data: unsafe { &mut *self.data.get() },
+		}
+	}
+}
+
+impl<T: ?Sized + Default> Default for Spinlock<T> {
+	fn default() -> Spinlock<T> {
+		Spinlock::new(Default::default())
+	}
+}
+
+impl<'a, T: ?Sized> Deref for SpinlockGuard<'a, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		self.data
+	}
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinlockGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.data
+	}
+}
+
+impl<'a, T: ?Sized> Drop for SpinlockGuard<'a, T> {
+	/// The dropping of the SpinlockGuard will release the lock it was created from.
+	fn drop(&mut self) {
+		#[cfg(feature = "lock_validator")]
+		lockdep::after_release(self.class);
+
+		self.dequeue.fetch_add(1, Ordering::SeqCst);
+	}
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
+unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
@@ -0,0 +1,44 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use arch::percore::core_scheduler;
+use core::time::Duration;
+use synch::futex;
+
+/// Returns `0` at once if `*addr != expected`. Otherwise blocks the calling
+/// task until it is woken via `sys_futex_wake` or `timeout_ns` nanoseconds
+/// have elapsed (when non-zero). Returns `0` on a (possibly spurious)
+/// wakeup and `-ETIMEDOUT` if the timeout expired first.
+#[no_mangle]
+pub extern "C" fn sys_futex_wait(addr: *const u32, expected: u32, timeout_ns: u64) -> i32 {
+	let timeout = if timeout_ns == 0 {
+		None
+	} else {
+		Some(Duration::from_nanos(timeout_ns))
+	};
+
+	futex::futex_wait(addr, expected, timeout)
+}
+
+/// Wakes up to `count` tasks waiting on the futex at `addr`. Pass
+/// `i32::MAX` for `count` to wake every waiter. Returns the number of tasks
+/// that were actually woken.
+#[no_mangle]
+pub extern "C" fn sys_futex_wake(addr: *const u32, count: i32) -> i32 {
+	futex::futex_wake(addr, count)
+}
+
+/// Registers `dtor` to be called with `t` when the calling task exits, in
+/// reverse registration order. Backs `#[thread_local]` destructor support
+/// for a `std` port targeting this kernel.
+#[no_mangle]
+pub extern "C" fn sys_thread_local_dtor(t: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
+	core_scheduler()
+		.current_task
+		.lock()
+		.register_tls_dtor(t, dtor);
+}